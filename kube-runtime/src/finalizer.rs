@@ -0,0 +1,428 @@
+//! Manages the lifecycle of one or more named finalizers on an object
+use kube::{
+    api::{Api, Meta, Patch, PatchParams},
+    error::ErrorResponse,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::{future::Future, pin::Pin};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error<ReconcilerErr: std::error::Error + 'static> {
+    #[error("the reconciler failed to apply for finalizer {finalizer:?}: {source}")]
+    ApplyFailed {
+        finalizer: String,
+        #[source]
+        source: ReconcilerErr,
+    },
+    #[error("the reconciler failed to clean up for finalizer {finalizer:?}: {source}")]
+    CleanupFailed {
+        finalizer: String,
+        #[source]
+        source: ReconcilerErr,
+    },
+    #[error("failed to add finalizer(s): {0}")]
+    AddFinalizer(#[source] kube::Error),
+    #[error("failed to remove finalizer {finalizer:?}: {source}")]
+    RemoveFinalizer {
+        finalizer: String,
+        #[source]
+        source: kube::Error,
+    },
+}
+
+/// A reconciliation event seen by a finalizer handler
+#[derive(Debug)]
+pub enum Event<K> {
+    /// The object is live, and should be reconciled normally
+    Apply(K),
+    /// The object is pending deletion, and the handler's cleanup should run
+    Cleanup(K),
+}
+
+fn current_finalizers<K: Meta>(obj: &K) -> Vec<String> {
+    obj.meta().finalizers.clone().unwrap_or_default()
+}
+
+fn is_deleting<K: Meta>(obj: &K) -> bool {
+    obj.meta().deletion_timestamp.is_some()
+}
+
+async fn patch_finalizers<K>(api: &Api<K>, name: &str, finalizers: Vec<String>) -> kube::Result<()>
+where
+    K: Clone + DeserializeOwned + Meta,
+{
+    match api
+        .patch(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "metadata": { "finalizers": finalizers } })),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        // The object is already gone, so there's nothing left of it to finalize.
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reconciles an object whose lifecycle is gated by a single named finalizer
+///
+/// `reconcile` is run with `Event::Apply` while the object is live, and with `Event::Cleanup` once
+/// it's pending deletion and still carries `finalizer_name`. The finalizer is added the first time
+/// an object without it is seen, and is only removed after `Event::Cleanup`'s handler succeeds, so a
+/// failed cleanup safely requeues rather than letting the apiserver delete the object before it runs.
+/// If the object is pending deletion but no longer carries `finalizer_name` (cleanup already ran and
+/// succeeded on a previous pass, or it was never added), `reconcile` is *not* run again, since the
+/// handler may not be safe to run twice; this is a benign no-op, so `Ok(T::default())` is returned
+/// rather than an error, matching how [`finalizers`] treats an already-removed finalizer.
+pub async fn finalizer<K, ReconcileFut, T, ReconcilerErr>(
+    api: &Api<K>,
+    finalizer_name: &str,
+    obj: K,
+    reconcile: impl FnOnce(Event<K>) -> ReconcileFut,
+) -> Result<T, Error<ReconcilerErr>>
+where
+    K: Meta + Clone + DeserializeOwned,
+    ReconcileFut: Future<Output = Result<T, ReconcilerErr>>,
+    ReconcilerErr: std::error::Error + 'static,
+    T: Default,
+{
+    let existing = current_finalizers(&obj);
+    let has_finalizer = existing.iter().any(|f| f == finalizer_name);
+    let name = obj.name();
+
+    if !is_deleting(&obj) {
+        if !has_finalizer {
+            let mut wanted = existing;
+            wanted.push(finalizer_name.to_string());
+            patch_finalizers(api, &name, wanted).await.map_err(Error::AddFinalizer)?;
+        }
+        reconcile(Event::Apply(obj)).await.map_err(|source| Error::ApplyFailed {
+            finalizer: finalizer_name.to_string(),
+            source,
+        })
+    } else if has_finalizer {
+        let result = reconcile(Event::Cleanup(obj))
+            .await
+            .map_err(|source| Error::CleanupFailed {
+                finalizer: finalizer_name.to_string(),
+                source,
+            })?;
+        let remaining = existing.into_iter().filter(|f| f != finalizer_name).collect();
+        patch_finalizers(api, &name, remaining)
+            .await
+            .map_err(|source| Error::RemoveFinalizer {
+                finalizer: finalizer_name.to_string(),
+                source,
+            })?;
+        Ok(result)
+    } else {
+        // Already removed (or never applied): nothing left here for us to do. In particular, don't
+        // run `reconcile` again, since the handler already ran (and removed the finalizer) on a
+        // previous pass, and may not be safe to run twice. This is expected, not an error: with
+        // several finalizers racing to clean up the same object, every controller but the last to
+        // finish its own cleanup will keep observing the object until it's finally gone.
+        Ok(T::default())
+    }
+}
+
+/// A handler's future, boxed so that handlers of different shapes can share one `finalizers` slice
+pub type HandlerFuture<'a, T, ReconcilerErr> = Pin<Box<dyn Future<Output = Result<T, ReconcilerErr>> + Send + 'a>>;
+
+/// One named finalizer's handler, as accepted by [`finalizers`]
+pub type Handler<'h, K, T, ReconcilerErr> = dyn Fn(Event<K>) -> HandlerFuture<'h, T, ReconcilerErr> + Sync;
+
+/// Reconciles an object whose lifecycle is gated by several independently-named finalizers
+///
+/// Each `(name, handler)` pair owns one entry in `metadata.finalizers`. While the object is live,
+/// every handler's `Event::Apply` runs, in the order given, and any of the named finalizers missing
+/// from the object are added in a single patch. Once the object is pending deletion, each handler's
+/// `Event::Cleanup` runs in order, and that finalizer is removed as soon as (and only once) its
+/// handler succeeds: a handler that fails stops the run there, leaving its finalizer — and any after
+/// it — in place, so the next reconcile resumes cleanup from where it left off rather than releasing
+/// the object with some cleanup steps skipped.
+pub async fn finalizers<'h, K, T, ReconcilerErr>(
+    api: &Api<K>,
+    finalizers: &[(&str, &Handler<'h, K, T, ReconcilerErr>)],
+    obj: K,
+) -> Result<Vec<T>, Error<ReconcilerErr>>
+where
+    K: Meta + Clone + DeserializeOwned,
+    ReconcilerErr: std::error::Error + 'static,
+{
+    let existing = current_finalizers(&obj);
+    let name = obj.name();
+
+    if !is_deleting(&obj) {
+        let mut wanted = existing.clone();
+        for (finalizer_name, _) in finalizers {
+            if !wanted.iter().any(|f| f == finalizer_name) {
+                wanted.push((*finalizer_name).to_string());
+            }
+        }
+        if wanted != existing {
+            patch_finalizers(api, &name, wanted).await.map_err(Error::AddFinalizer)?;
+        }
+
+        let mut results = Vec::with_capacity(finalizers.len());
+        for (finalizer_name, handler) in finalizers {
+            let result = handler(Event::Apply(obj.clone()))
+                .await
+                .map_err(|source| Error::ApplyFailed {
+                    finalizer: finalizer_name.to_string(),
+                    source,
+                })?;
+            results.push(result);
+        }
+        Ok(results)
+    } else {
+        let mut remaining = existing;
+        let mut results = Vec::with_capacity(finalizers.len());
+        for (finalizer_name, handler) in finalizers {
+            if !remaining.iter().any(|f| f == finalizer_name) {
+                // A previous reconcile already finished this one.
+                continue;
+            }
+            let result = handler(Event::Cleanup(obj.clone()))
+                .await
+                .map_err(|source| Error::CleanupFailed {
+                    finalizer: finalizer_name.to_string(),
+                    source,
+                })?;
+            remaining.retain(|f| f != finalizer_name);
+            patch_finalizers(api, &name, remaining.clone())
+                .await
+                .map_err(|source| Error::RemoveFinalizer {
+                    finalizer: finalizer_name.to_string(),
+                    source,
+                })?;
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{finalizer, finalizers, Error, Event, Handler, HandlerFuture};
+    use futures::FutureExt;
+    use http::{Request, Response, StatusCode};
+    use hyper::Body;
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::{api::Api, Client};
+    use serde_json::{json, Value};
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+    use tower::service_fn;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("{0}")]
+    struct TestError(String);
+
+    /// One observed request against the mocked API server: its method, its path, and its JSON body
+    type Call = (http::Method, String, Value);
+
+    /// A `kube::Client` backed by a scripted sequence of `(status, body)` responses
+    ///
+    /// Each call the `Api` makes against it is recorded (in order) into the returned `Vec`, and
+    /// answered with the next response in `responses`. Panics if more calls are made than there are
+    /// scripted responses, since that means a test's assumption about how many requests `finalizer`
+    /// or `finalizers` makes no longer holds.
+    fn mock_client(responses: Vec<(StatusCode, Value)>) -> (Client, Arc<Mutex<Vec<Call>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let responses = Arc::new(Mutex::new(responses.into_iter()));
+        let calls2 = calls.clone();
+        let service = service_fn(move |req: Request<Body>| {
+            let calls = calls2.clone();
+            let responses = responses.clone();
+            async move {
+                let method = req.method().clone();
+                let uri = req.uri().to_string();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let body = if body.is_empty() {
+                    Value::Null
+                } else {
+                    serde_json::from_slice(&body).unwrap()
+                };
+                calls.lock().unwrap().push((method, uri, body));
+                let (status, body) = responses
+                    .lock()
+                    .unwrap()
+                    .next()
+                    .expect("test made more requests than it scripted responses for");
+                Ok::<_, tower::BoxError>(
+                    Response::builder()
+                        .status(status)
+                        .header("content-type", "application/json")
+                        .body(Body::from(serde_json::to_vec(&body).unwrap()))
+                        .unwrap(),
+                )
+            }
+        });
+        (Client::new(kube::service::Service::new(service)), calls)
+    }
+
+    fn configmap(name: &str, finalizers: &[&str], deleting: bool) -> ConfigMap {
+        let mut meta = json!({
+            "name": name,
+            "namespace": "default",
+            "finalizers": finalizers,
+        });
+        if deleting {
+            meta["deletionTimestamp"] = json!("2021-01-01T00:00:00Z");
+        }
+        serde_json::from_value(json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": meta,
+        }))
+        .unwrap()
+    }
+
+    fn patched_configmap(name: &str, finalizers: &[&str]) -> Value {
+        json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": { "name": name, "namespace": "default", "finalizers": finalizers },
+        })
+    }
+
+    #[tokio::test]
+    async fn finalizer_should_add_missing_finalizer_on_apply() {
+        let (client, calls) = mock_client(vec![(StatusCode::OK, patched_configmap("cm", &["my.finalizer"]))]);
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let obj = configmap("cm", &[], false);
+
+        let result: Result<(), Error<Infallible>> = finalizer(&api, "my.finalizer", obj, |event| async move {
+            assert!(matches!(event, Event::Apply(_)));
+            Ok(())
+        })
+        .await;
+
+        assert!(result.is_ok());
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].2["metadata"]["finalizers"], json!(["my.finalizer"]));
+    }
+
+    #[tokio::test]
+    async fn finalizer_should_only_remove_finalizer_after_cleanup_succeeds() {
+        let (client, calls) = mock_client(vec![(StatusCode::OK, patched_configmap("cm", &[]))]);
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let obj = configmap("cm", &["my.finalizer"], true);
+
+        let result: Result<(), Error<Infallible>> = finalizer(&api, "my.finalizer", obj, |event| async move {
+            assert!(matches!(event, Event::Cleanup(_)));
+            Ok(())
+        })
+        .await;
+
+        assert!(result.is_ok());
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1, "finalizer should only be removed, not also re-added");
+        assert_eq!(calls[0].2["metadata"]["finalizers"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn finalizer_should_treat_404_on_removal_as_success() {
+        let (client, _calls) = mock_client(vec![(
+            StatusCode::NOT_FOUND,
+            json!({ "status": "Failure", "code": 404, "reason": "NotFound" }),
+        )]);
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let obj = configmap("cm", &["my.finalizer"], true);
+
+        let result: Result<(), Error<Infallible>> =
+            finalizer(&api, "my.finalizer", obj, |_event| async move { Ok(()) }).await;
+
+        assert!(result.is_ok(), "a 404 while removing the finalizer means there's nothing left to finalize");
+    }
+
+    #[tokio::test]
+    async fn finalizer_should_not_rerun_cleanup_once_finalizer_is_already_gone() {
+        // No responses scripted: the mock client panics if `finalizer` makes any request at all.
+        let (client, calls) = mock_client(vec![]);
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let obj = configmap("cm", &[], true);
+
+        let reconcile_ran = Arc::new(Mutex::new(false));
+        let reconcile_ran2 = reconcile_ran.clone();
+        let result: Result<(), Error<Infallible>> = finalizer(&api, "my.finalizer", obj, move |_event| {
+            *reconcile_ran2.lock().unwrap() = true;
+            async move { Ok(()) }
+        })
+        .await;
+
+        assert!(matches!(result, Ok(())), "a missing finalizer on a deleting object is a benign no-op, not an error");
+        assert!(!*reconcile_ran.lock().unwrap(), "cleanup must not run again once the finalizer is already gone");
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finalizers_should_add_all_missing_finalizers_in_one_patch() {
+        let (client, calls) = mock_client(vec![(StatusCode::OK, patched_configmap("cm", &["a", "b"]))]);
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let obj = configmap("cm", &[], false);
+
+        let handler_a: &Handler<'_, ConfigMap, (), Infallible> = &|event| -> HandlerFuture<(), Infallible> {
+            async move {
+                assert!(matches!(event, Event::Apply(_)));
+                Ok(())
+            }
+            .boxed()
+        };
+        let handler_b: &Handler<'_, ConfigMap, (), Infallible> = &|event| -> HandlerFuture<(), Infallible> {
+            async move {
+                assert!(matches!(event, Event::Apply(_)));
+                Ok(())
+            }
+            .boxed()
+        };
+
+        let result = finalizers(&api, &[("a", handler_a), ("b", handler_b)], obj).await;
+
+        assert!(result.is_ok());
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].2["metadata"]["finalizers"], json!(["a", "b"]));
+    }
+
+    #[tokio::test]
+    async fn finalizers_should_leave_failed_and_later_finalizers_in_place() {
+        // Only one patch expected: removing "a" after its handler succeeds. "b"'s handler fails
+        // before ever reaching a patch call for "b" (or "c", which comes after it).
+        let (client, calls) = mock_client(vec![(StatusCode::OK, patched_configmap("cm", &["b", "c"]))]);
+        let api = Api::<ConfigMap>::namespaced(client, "default");
+        let obj = configmap("cm", &["a", "b", "c"], true);
+
+        let handler_a: &Handler<'_, ConfigMap, (), TestError> = &|event| -> HandlerFuture<(), TestError> {
+            async move {
+                assert!(matches!(event, Event::Cleanup(_)));
+                Ok(())
+            }
+            .boxed()
+        };
+        let handler_b: &Handler<'_, ConfigMap, (), TestError> = &|event| -> HandlerFuture<(), TestError> {
+            async move {
+                assert!(matches!(event, Event::Cleanup(_)));
+                Err(TestError("b failed".to_string()))
+            }
+            .boxed()
+        };
+        let handler_c: &Handler<'_, ConfigMap, (), TestError> = &|_event| -> HandlerFuture<(), TestError> {
+            async move { panic!("handler for \"c\" must not run once \"b\" has failed") }.boxed()
+        };
+
+        let result = finalizers(&api, &[("a", handler_a), ("b", handler_b), ("c", handler_c)], obj).await;
+
+        assert!(matches!(result, Err(Error::CleanupFailed { finalizer, .. }) if finalizer == "b"));
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1, "only \"a\"'s removal should have been patched");
+        assert_eq!(calls[0].2["metadata"]["finalizers"], json!(["b", "c"]));
+    }
+}
@@ -0,0 +1,12 @@
+//! Runtime helpers for building Kubernetes controllers and operators
+//!
+//! This is the lower-level half of `kube`'s operator story: given a stream of watch events for a
+//! resource, these modules help you turn that into a reconciliation loop, a local cache, or both.
+
+pub mod finalizer;
+pub mod reflector;
+pub mod watcher;
+
+pub use finalizer::finalizer;
+pub use reflector::reflector;
+pub use watcher::watcher;
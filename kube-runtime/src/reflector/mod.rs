@@ -1,27 +1,72 @@
 mod object_ref;
 pub mod store;
 
-pub use self::object_ref::{ErasedResource, ObjectRef, RuntimeResource};
+pub use self::object_ref::{ErasedObjectRef, ErasedResource, ObjectRef, RuntimeResource};
 use crate::watcher;
-use futures::{Stream, TryStreamExt};
-use kube::api::Meta;
+use futures::{stream, Stream, StreamExt};
 pub use store::Store;
+#[cfg(feature = "unstable-runtime-subscribe")]
+pub use store::{store_shared, ReflectHandle};
 
-/// Caches objects from `watcher::Event`s to a local `Store`
+/// Caches objects from `watcher::Event`s to a local `Store`, alongside any `Writer` subscribers
 ///
 /// Keep in mind that the `Store` is just a cache, and may be out of date.
 ///
 /// Note: It is a bad idea to feed a single `reflector` from multiple `watcher`s, since
-/// the whole `Store` will be cleared whenever any of them emits a `Restarted` event.
+/// the whole `Store` will be cleared whenever any of them emits a `Restarted` event. Use
+/// [`reflector_multi`] if you need to shard one `Store` across several `watcher`s.
+///
+/// If the `Writer` has subscribers (see `store::Writer::subscribe` / `store::store_shared`), each
+/// event is also dispatched to them, after it has been applied to the `Store`, before being passed
+/// on downstream.
 ///
 /// # Migration from kube::runtime
 ///
 /// Similar to the legacy `kube::runtime::Reflector`, and the caching half of client-go's `Reflector`
-pub fn reflector<K: Meta + Clone, W: Stream<Item = watcher::Result<watcher::Event<K>>>>(
-    mut store: store::Writer<K>,
+pub fn reflector<K: RuntimeResource, W: Stream<Item = watcher::Result<watcher::Event<K>>>>(
+    store: store::Writer<K>,
     stream: W,
 ) -> impl Stream<Item = W::Item> {
-    stream.inspect_ok(move |event| store.apply_watcher_event(event))
+    stream::unfold((store, Box::pin(stream)), |(mut store, mut stream)| async move {
+        let event = stream.next().await?;
+        if let Ok(event) = &event {
+            store.apply_watcher_event(event);
+            #[cfg(feature = "unstable-runtime-subscribe")]
+            store.dispatch_event(event).await;
+        }
+        Some((event, (store, stream)))
+    })
+}
+
+/// Like [`reflector`], but shards a single `Store` across several `watcher` streams
+///
+/// Each `stream` in `streams` is assigned a source id (its index), and a `Restarted` it emits only
+/// evicts and replaces the objects the `Store` previously saw from that same stream — objects from
+/// the other streams are left alone. This is the safe way to watch, say, several namespaces or
+/// several kinds into one shared cache, which [`reflector`] explicitly warns against.
+pub fn reflector_multi<K, W>(
+    store: store::Writer<K>,
+    streams: impl IntoIterator<Item = W>,
+) -> impl Stream<Item = watcher::Result<watcher::Event<K>>>
+where
+    K: RuntimeResource,
+    W: Stream<Item = watcher::Result<watcher::Event<K>>> + Send + 'static,
+{
+    let sources = streams
+        .into_iter()
+        .enumerate()
+        .map(|(source_id, stream)| stream.map(move |event| (source_id, event)).boxed())
+        .collect::<Vec<_>>();
+    stream::unfold(
+        (store, stream::select_all(sources)),
+        |(mut store, mut stream)| async move {
+            let (source_id, event) = stream.next().await?;
+            if let Ok(event) = &event {
+                store.apply_watcher_event_for_source(source_id, event);
+            }
+            Some((event, (store, stream)))
+        },
+    )
 }
 
 #[cfg(test)]
@@ -140,4 +185,206 @@ mod tests {
         assert_eq!(store.get(&ObjectRef::from_obj(&cm_a)), None);
         assert_eq!(store.get(&ObjectRef::from_obj(&cm_b)), Some(cm_b));
     }
+
+    #[tokio::test]
+    async fn reflector_multi_restart_should_only_evict_its_own_source() {
+        let store_w = store::Writer::default();
+        let store = store_w.as_reader();
+        let cm_a = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("a".to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        let cm_b = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("b".to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        // Source 0 restarts with an empty list, which should only evict what source 0 had
+        // previously reported (`cm_a`), leaving source 1's `cm_b` untouched.
+        super::reflector_multi(store_w, vec![
+            stream::iter(vec![
+                Ok(watcher::Event::Restarted(vec![cm_a.clone()])),
+                Ok(watcher::Event::Restarted(vec![])),
+            ])
+            .boxed(),
+            stream::iter(vec![Ok(watcher::Event::Applied(cm_b.clone()))]).boxed(),
+        ])
+        .map(|_| ())
+        .collect::<()>()
+        .await;
+        assert_eq!(store.get(&ObjectRef::from_obj(&cm_a)), None);
+        assert_eq!(store.get(&ObjectRef::from_obj(&cm_b)), Some(cm_b));
+    }
+
+    #[tokio::test]
+    async fn reflector_should_index_owner_references() {
+        use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+
+        let store_w = store::Writer::default();
+        let store = store_w.as_reader();
+        let owner = OwnerReference {
+            api_version: "v1".to_string(),
+            kind: "ConfigMap".to_string(),
+            name: "owner".to_string(),
+            uid: "uid-1".to_string(),
+            ..OwnerReference::default()
+        };
+        let owned = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("owned".to_string()),
+                namespace: Some("ns".to_string()),
+                owner_references: Some(vec![owner.clone()]),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        reflector(store_w, stream::iter(vec![Ok(watcher::Event::Applied(owned.clone()))]))
+            .map(|_| ())
+            .collect::<()>()
+            .await;
+
+        let owner_ref = super::ErasedObjectRef::from_owner_ref(Some("ns"), &owner);
+        assert_eq!(store.get_owners(&ObjectRef::from_obj(&owned)), vec![owner_ref.clone()]);
+        assert_eq!(store.get_owned(&owner_ref), vec![ObjectRef::from_obj(&owned)]);
+    }
+
+    fn sync_label(cm: &ConfigMap) -> Vec<String> {
+        cm.metadata
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get("sync"))
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reflector_should_maintain_secondary_indexes() {
+        let store_w = store::Writer::default().with_index("by_sync_label", sync_label);
+        let store = store_w.as_reader();
+        let synced = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("synced".to_string()),
+                labels: Some({
+                    let mut labels = BTreeMap::new();
+                    labels.insert("sync".to_string(), "true".to_string());
+                    labels
+                }),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        let unsynced = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("unsynced".to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        };
+        reflector(
+            store_w,
+            stream::iter(vec![
+                Ok(watcher::Event::Applied(synced.clone())),
+                Ok(watcher::Event::Applied(unsynced)),
+            ]),
+        )
+        .map(|_| ())
+        .collect::<()>()
+        .await;
+
+        assert_eq!(store.by_index("by_sync_label", "true"), vec![synced]);
+        assert_eq!(store.by_index("by_sync_label", "false"), vec![]);
+        assert_eq!(store.by_index("no_such_index", "true"), vec![]);
+    }
+}
+
+#[cfg(all(test, feature = "unstable-runtime-subscribe"))]
+mod subscribe_tests {
+    use super::{reflector, store, ObjectRef};
+    use crate::watcher;
+    use futures::{stream, StreamExt};
+    use k8s_openapi::{api::core::v1::ConfigMap, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+    use std::time::Duration;
+
+    fn cm(name: &str) -> ConfigMap {
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..ObjectMeta::default()
+            },
+            ..ConfigMap::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn late_subscriber_should_see_a_consistent_store() {
+        let mut store_w = store::Writer::default();
+        let handle = store_w.subscribe(10).await;
+        let cm_a = cm("a");
+        let cm_b = cm("b");
+        reflector(
+            store_w,
+            stream::iter(vec![
+                Ok(watcher::Event::Applied(cm_a.clone())),
+                Ok(watcher::Event::Applied(cm_b.clone())),
+            ]),
+        )
+        .map(|_| ())
+        .collect::<()>()
+        .await;
+
+        // Subscribing only after the reflector has already finished should still see everything
+        // that's in the store, since the store itself (not the channel) is the source of truth for
+        // a subscriber's `get`/`state`.
+        let late = handle.resubscribe(10).await;
+        assert_eq!(late.get(&ObjectRef::from_obj(&cm_a)), Some(cm_a));
+        assert_eq!(late.get(&ObjectRef::from_obj(&cm_b)), Some(cm_b));
+    }
+
+    #[tokio::test]
+    async fn restarted_should_be_propagated_to_subscribers() {
+        let mut store_w = store::Writer::default();
+        let mut handle = store_w.subscribe(10).await;
+        let cm_a = cm("a");
+        let cm_b = cm("b");
+        reflector(
+            store_w,
+            stream::iter(vec![Ok(watcher::Event::Restarted(vec![
+                cm_a.clone(),
+                cm_b.clone(),
+            ]))]),
+        )
+        .for_each(|_| async {})
+        .await;
+
+        assert_eq!(handle.next().await, Some(cm_a));
+        assert_eq!(handle.next().await, Some(cm_b));
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_should_stall_the_writer() {
+        let mut store_w = store::Writer::default();
+        let _handle = store_w.subscribe(1).await; // never drained
+        let cm_a = cm("a");
+        let cm_b = cm("b");
+        let driver = reflector(
+            store_w,
+            stream::iter(vec![
+                Ok(watcher::Event::Applied(cm_a.clone())),
+                Ok(watcher::Event::Applied(cm_b.clone())),
+            ]),
+        )
+        .for_each(|_| async {});
+
+        // The second `Applied` can't be dispatched until the bounded channel is drained, so the
+        // whole reflector stalls rather than completing.
+        assert!(tokio::time::timeout(Duration::from_millis(50), driver)
+            .await
+            .is_err());
+    }
 }
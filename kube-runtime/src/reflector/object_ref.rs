@@ -0,0 +1,188 @@
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::OwnerReference, Resource};
+use kube::api::Meta;
+use std::{
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
+
+/// The group/version/kind of a Kubernetes object, with the Rust type erased
+///
+/// This is the part of an [`ObjectRef`] that can be compared and stored without requiring the
+/// caller to know `K` statically, which is what lets a [`store::Store`](crate::reflector::store::Store)
+/// key a heterogeneous-kind index (such as an owner-reference index) by GVK + name rather than by type.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ErasedResource {
+    pub api_version: String,
+    pub kind: String,
+}
+
+impl ErasedResource {
+    fn erase_for<K: Resource>() -> Self {
+        Self {
+            api_version: K::API_VERSION.to_string(),
+            kind: K::KIND.to_string(),
+        }
+    }
+}
+
+impl Debug for ErasedResource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}/{}", self.api_version, self.kind)
+    }
+}
+
+/// The bound that the reflector machinery requires of any kind it keeps references to
+pub trait RuntimeResource: Meta + Resource + Clone {}
+impl<K: Meta + Resource + Clone> RuntimeResource for K {}
+
+/// A reference to a Kubernetes object of a statically known kind `K`
+///
+/// Cheap to clone and hash, and carries no data from the object other than its identity, so it's
+/// suitable as a cache key (see [`store::Store`](crate::reflector::store::Store)).
+pub struct ObjectRef<K: RuntimeResource> {
+    pub name: String,
+    pub namespace: Option<String>,
+    resource: ErasedResource,
+    /// The object's `metadata.uid`, if known
+    ///
+    /// Deliberately excluded from identity (`PartialEq`/`Hash`): the cache key a reflector `Store`
+    /// uses must stay name+namespace+GVK, so that a delete-then-recreate under the same name
+    /// replaces the old cache entry rather than leaving it orphaned under a stale key.
+    uid: Option<String>,
+    _kind: PhantomData<fn() -> K>,
+}
+
+impl<K: RuntimeResource> ObjectRef<K> {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            namespace: None,
+            resource: ErasedResource::erase_for::<K>(),
+            uid: None,
+            _kind: PhantomData,
+        }
+    }
+
+    #[must_use]
+    pub fn within(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    pub fn from_obj(obj: &K) -> Self {
+        Self {
+            name: obj.name(),
+            namespace: obj.namespace(),
+            resource: ErasedResource::erase_for::<K>(),
+            uid: obj.meta().uid.clone(),
+            _kind: PhantomData,
+        }
+    }
+
+    /// Erases `K`, keeping only the GVK + namespace + name + uid identity
+    ///
+    /// Used to look an object up from an index (such as the owner-reference index) that is shared
+    /// across kinds. Only meaningful for a ref built via [`Self::from_obj`]: one built via
+    /// [`Self::new`] has no `uid` to assert, and so can't match any real owner.
+    pub fn erase(&self) -> ErasedObjectRef {
+        ErasedObjectRef {
+            name: self.name.clone(),
+            namespace: self.namespace.clone(),
+            resource: self.resource.clone(),
+            uid: self.uid.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl<K: RuntimeResource> Clone for ObjectRef<K> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            namespace: self.namespace.clone(),
+            resource: self.resource.clone(),
+            uid: self.uid.clone(),
+            _kind: PhantomData,
+        }
+    }
+}
+
+impl<K: RuntimeResource> PartialEq for ObjectRef<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.namespace == other.namespace && self.resource == other.resource
+    }
+}
+impl<K: RuntimeResource> Eq for ObjectRef<K> {}
+
+impl<K: RuntimeResource> Hash for ObjectRef<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.namespace.hash(state);
+        self.resource.hash(state);
+    }
+}
+
+impl<K: RuntimeResource> Debug for ObjectRef<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ObjectRef")
+            .field("resource", &self.resource)
+            .field("namespace", &self.namespace)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<K: RuntimeResource> Display for ObjectRef<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match &self.namespace {
+            Some(namespace) => write!(f, "{}/{} ({:?})", namespace, self.name, self.resource),
+            None => write!(f, "{} ({:?})", self.name, self.resource),
+        }
+    }
+}
+
+/// A reference to a Kubernetes object whose Rust type is not known statically
+///
+/// This is what an owner reference resolves to: `metadata.ownerReferences` only carries
+/// `apiVersion`/`kind` as strings, so there is no `K` to parameterize an [`ObjectRef`] with.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ErasedObjectRef {
+    pub name: String,
+    pub namespace: Option<String>,
+    pub resource: ErasedResource,
+    /// The referent's `metadata.uid`
+    ///
+    /// Included in identity (not just carried as data) so that a deleted-and-recreated owner,
+    /// which reuses the same name, isn't conflated with its predecessor.
+    pub uid: String,
+}
+
+impl ErasedObjectRef {
+    /// Resolves an object's `ownerReferences` entry into the reference it points at
+    ///
+    /// `OwnerReference` never carries a namespace of its own: owners of a namespaced object are
+    /// required to live in the same namespace as the object they own, so the child's namespace is
+    /// reused here.
+    pub fn from_owner_ref(namespace: Option<&str>, owner: &OwnerReference) -> Self {
+        Self {
+            name: owner.name.clone(),
+            namespace: namespace.map(String::from),
+            resource: ErasedResource {
+                api_version: owner.api_version.clone(),
+                kind: owner.kind.clone(),
+            },
+            uid: owner.uid.clone(),
+        }
+    }
+}
+
+impl Debug for ErasedObjectRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ErasedObjectRef")
+            .field("resource", &self.resource)
+            .field("namespace", &self.namespace)
+            .field("name", &self.name)
+            .field("uid", &self.uid)
+            .finish()
+    }
+}
@@ -0,0 +1,470 @@
+use super::{ErasedObjectRef, ObjectRef, RuntimeResource};
+use crate::watcher;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+#[cfg(feature = "unstable-runtime-subscribe")]
+use futures::Stream;
+#[cfg(feature = "unstable-runtime-subscribe")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "unstable-runtime-subscribe")]
+use tokio::sync::{mpsc, Mutex};
+
+type Cache<K> = Arc<std::sync::RwLock<HashMap<ObjectRef<K>, Arc<K>>>>;
+
+/// The reverse of `metadata.ownerReferences`: who owns whom, and who is owned by whom
+///
+/// Keyed on the owner's identity rather than `K`, since `ownerReferences` only ever carries the
+/// owner's `apiVersion`/`kind`/`name`, which may well belong to a different kind than `K`.
+struct OwnerIndex<K: RuntimeResource> {
+    owned_by: HashMap<ErasedObjectRef, HashSet<ObjectRef<K>>>,
+    owners_of: HashMap<ObjectRef<K>, Vec<ErasedObjectRef>>,
+}
+
+impl<K: RuntimeResource> Default for OwnerIndex<K> {
+    fn default() -> Self {
+        Self {
+            owned_by: HashMap::new(),
+            owners_of: HashMap::new(),
+        }
+    }
+}
+
+impl<K: RuntimeResource> OwnerIndex<K> {
+    fn owner_refs_of(obj: &K) -> Vec<ErasedObjectRef> {
+        let namespace = obj.namespace();
+        obj.meta()
+            .owner_references
+            .iter()
+            .flatten()
+            .map(|owner| ErasedObjectRef::from_owner_ref(namespace.as_deref(), owner))
+            .collect()
+    }
+
+    fn remove(&mut self, child_ref: &ObjectRef<K>) {
+        if let Some(owners) = self.owners_of.remove(child_ref) {
+            for owner in owners {
+                if let Some(children) = self.owned_by.get_mut(&owner) {
+                    children.remove(child_ref);
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, obj: &K) {
+        let child_ref = ObjectRef::from_obj(obj);
+        self.remove(&child_ref);
+        let owners = Self::owner_refs_of(obj);
+        for owner in &owners {
+            self.owned_by.entry(owner.clone()).or_default().insert(child_ref.clone());
+        }
+        self.owners_of.insert(child_ref, owners);
+    }
+
+    fn restart(&mut self, new_objs: &[K]) {
+        *self = Self::default();
+        for obj in new_objs {
+            self.insert(obj);
+        }
+    }
+}
+
+/// A user-supplied function from an object to the secondary index keys it should be reachable under
+///
+/// Registered via `Writer::with_index` (e.g. a label value, a spec field, or the namespace). Boxed
+/// rather than a bare `fn` pointer so the closure can capture the parameter it's indexing on (the
+/// label key to read, the namespace to partition by, ...) instead of hard-coding it.
+pub type IndexFn<K> = Box<dyn Fn(&K) -> Vec<String> + Send + Sync>;
+
+/// Caller-defined secondary indexes over a `Store`, keyed by an arbitrary string derived from `K`
+///
+/// Unlike [`OwnerIndex`], which is always maintained, these only exist if the `Writer` was given
+/// `index_fn`s to compute them with.
+struct SecondaryIndexes<K: RuntimeResource> {
+    index_fns: HashMap<&'static str, IndexFn<K>>,
+    data: HashMap<&'static str, HashMap<String, HashSet<ObjectRef<K>>>>,
+    keys_of: HashMap<ObjectRef<K>, HashMap<&'static str, Vec<String>>>,
+}
+
+impl<K: RuntimeResource> Default for SecondaryIndexes<K> {
+    fn default() -> Self {
+        Self {
+            index_fns: HashMap::new(),
+            data: HashMap::new(),
+            keys_of: HashMap::new(),
+        }
+    }
+}
+
+impl<K: RuntimeResource> SecondaryIndexes<K> {
+    fn remove(&mut self, child_ref: &ObjectRef<K>) {
+        if let Some(keys_by_index) = self.keys_of.remove(child_ref) {
+            for (name, keys) in keys_by_index {
+                if let Some(index) = self.data.get_mut(name) {
+                    for key in keys {
+                        if let Some(children) = index.get_mut(&key) {
+                            children.remove(child_ref);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, obj: &K) {
+        let child_ref = ObjectRef::from_obj(obj);
+        self.remove(&child_ref);
+        let mut keys_by_index = HashMap::new();
+        for (&name, index_fn) in &self.index_fns {
+            let keys = index_fn(obj);
+            for key in &keys {
+                self.data
+                    .entry(name)
+                    .or_default()
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(child_ref.clone());
+            }
+            keys_by_index.insert(name, keys);
+        }
+        self.keys_of.insert(child_ref, keys_by_index);
+    }
+
+    fn restart(&mut self, new_objs: &[K]) {
+        self.data.clear();
+        self.keys_of.clear();
+        for obj in new_objs {
+            self.insert(obj);
+        }
+    }
+}
+
+/// A read handle to a shared, eventually-consistent cache of objects of kind `K`
+///
+/// Kept up to date by a [`Writer`], which is usually driven by [`reflector`](super::reflector).
+/// Cloning a `Store` is cheap: it's just another handle onto the same underlying cache.
+pub struct Store<K: RuntimeResource> {
+    store: Cache<K>,
+    owner_index: Arc<std::sync::RwLock<OwnerIndex<K>>>,
+    secondary_indexes: Arc<std::sync::RwLock<SecondaryIndexes<K>>>,
+}
+
+impl<K: RuntimeResource> Store<K> {
+    /// Returns the latest cached version of the object, if it's present in the store
+    pub fn get(&self, obj_ref: &ObjectRef<K>) -> Option<K> {
+        self.store.read().unwrap().get(obj_ref).map(|obj| K::clone(obj))
+    }
+
+    /// Returns a snapshot of every object currently in the store
+    pub fn state(&self) -> Vec<K> {
+        self.store.read().unwrap().values().map(|obj| K::clone(obj)).collect()
+    }
+
+    /// Returns the owners of `obj_ref`, as read from its `metadata.ownerReferences`
+    ///
+    /// The owners are returned type-erased (`ErasedObjectRef`, not `ObjectRef<K>`), since
+    /// `ownerReferences` doesn't pin down a Rust type for them — they could be of any kind, not just
+    /// `K` — so there is no `ObjectRef<SomeOwnerKind>` this method could construct. This is a
+    /// deliberate deviation from a same-kind `ObjectRef` signature.
+    pub fn get_owners(&self, obj_ref: &ObjectRef<K>) -> Vec<ErasedObjectRef> {
+        self.owner_index
+            .read()
+            .unwrap()
+            .owners_of
+            .get(obj_ref)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns every object of kind `K` in the store whose `metadata.ownerReferences` names `owner`
+    ///
+    /// `owner` is type-erased (`&ErasedObjectRef`, not `&ObjectRef<K>`) for the same reason
+    /// [`Self::get_owners`] returns erased refs: the owner can be of any kind, so the caller can't
+    /// generally name it as an `ObjectRef<SomeOwnerKind>` without knowing that kind statically too.
+    pub fn get_owned(&self, owner: &ErasedObjectRef) -> Vec<ObjectRef<K>> {
+        self.owner_index
+            .read()
+            .unwrap()
+            .owned_by
+            .get(owner)
+            .map(|children| children.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns every object of kind `K` registered under `key` in the secondary index `name`
+    ///
+    /// Returns an empty `Vec` both when `name` doesn't exist and when no object currently has `key`;
+    /// the two cases are not distinguished.
+    pub fn by_index(&self, name: &str, key: &str) -> Vec<K> {
+        let refs = self
+            .secondary_indexes
+            .read()
+            .unwrap()
+            .data
+            .get(name)
+            .and_then(|index| index.get(key))
+            .cloned()
+            .unwrap_or_default();
+        let store = self.store.read().unwrap();
+        refs.iter().filter_map(|obj_ref| store.get(obj_ref)).map(|obj| K::clone(obj)).collect()
+    }
+}
+
+impl<K: RuntimeResource> Clone for Store<K> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            owner_index: self.owner_index.clone(),
+            secondary_indexes: self.secondary_indexes.clone(),
+        }
+    }
+}
+
+/// The shared subscriber list behind a [`Writer`]'s [`ReflectHandle`]s
+#[cfg(feature = "unstable-runtime-subscribe")]
+struct Dispatcher<K> {
+    subscribers: Vec<mpsc::Sender<Arc<K>>>,
+}
+
+#[cfg(feature = "unstable-runtime-subscribe")]
+impl<K> Default for Dispatcher<K> {
+    fn default() -> Self {
+        Self {
+            subscribers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "unstable-runtime-subscribe")]
+impl<K> Dispatcher<K> {
+    /// Broadcasts `obj` to every subscriber, awaiting the slowest one
+    ///
+    /// Subscriber channels are bounded, so a subscriber that falls behind applies backpressure to
+    /// this call (and hence to the `reflector` driving the `Writer`) rather than being dropped.
+    async fn broadcast(&mut self, obj: Arc<K>) {
+        let mut i = 0;
+        while i < self.subscribers.len() {
+            if self.subscribers[i].send(obj.clone()).await.is_ok() {
+                i += 1;
+            } else {
+                self.subscribers.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// A handle to a single subscriber of a [`Writer`]'s shared [`Store`]
+///
+/// Yields a clone of every object that is `Applied`, `Deleted`, or present in a `Restarted` batch,
+/// in the order it was committed to the `Store`, from the point this handle was created onwards.
+/// Derefs to a [`Store`] reader so a subscriber can look up related objects without a watch of its own.
+#[cfg(feature = "unstable-runtime-subscribe")]
+pub struct ReflectHandle<K: RuntimeResource> {
+    reader: Store<K>,
+    dispatcher: Arc<Mutex<Dispatcher<K>>>,
+    rx: mpsc::Receiver<Arc<K>>,
+}
+
+#[cfg(feature = "unstable-runtime-subscribe")]
+impl<K: RuntimeResource> ReflectHandle<K> {
+    /// Registers another subscriber against the same `Writer`, with its own bounded buffer
+    ///
+    /// Unlike `Writer::subscribe`, this doesn't need a `&mut Writer`, so it's how a handle that was
+    /// handed off to a task fans out further subscribers after the reflector has started running.
+    pub async fn resubscribe(&self, buffer: usize) -> Self {
+        let (tx, rx) = mpsc::channel(buffer);
+        self.dispatcher.lock().await.subscribers.push(tx);
+        Self {
+            reader: self.reader.clone(),
+            dispatcher: self.dispatcher.clone(),
+            rx,
+        }
+    }
+}
+
+#[cfg(feature = "unstable-runtime-subscribe")]
+impl<K: RuntimeResource> std::ops::Deref for ReflectHandle<K> {
+    type Target = Store<K>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
+
+#[cfg(feature = "unstable-runtime-subscribe")]
+impl<K: RuntimeResource> Stream for ReflectHandle<K> {
+    type Item = K;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.rx.poll_recv(cx).map(|opt| opt.map(|obj| K::clone(&obj)))
+    }
+}
+
+/// Writes new object events into a [`Store`]
+///
+/// Usually driven by [`reflector`](super::reflector), which applies each `watcher::Event` to the
+/// store (and, if there are subscribers, dispatches it to them) before passing it on unchanged.
+pub struct Writer<K: RuntimeResource> {
+    store: Cache<K>,
+    owner_index: Arc<std::sync::RwLock<OwnerIndex<K>>>,
+    secondary_indexes: Arc<std::sync::RwLock<SecondaryIndexes<K>>>,
+    /// The set of objects last seen from each source, only populated by `apply_watcher_event_for_source`
+    membership: HashMap<usize, HashSet<ObjectRef<K>>>,
+    #[cfg(feature = "unstable-runtime-subscribe")]
+    dispatcher: Arc<Mutex<Dispatcher<K>>>,
+}
+
+impl<K: RuntimeResource> Default for Writer<K> {
+    fn default() -> Self {
+        Self {
+            store: Default::default(),
+            owner_index: Default::default(),
+            secondary_indexes: Default::default(),
+            membership: HashMap::new(),
+            #[cfg(feature = "unstable-runtime-subscribe")]
+            dispatcher: Default::default(),
+        }
+    }
+}
+
+impl<K: RuntimeResource> Writer<K> {
+    /// Registers a secondary index, queryable afterwards via `Store::by_index(name, ..)`
+    ///
+    /// Must be called before the `Writer` starts receiving events: existing cache contents aren't
+    /// retroactively indexed, since there usually aren't any yet (the `Writer` was just created).
+    #[must_use]
+    pub fn with_index(self, name: &'static str, index_fn: impl Fn(&K) -> Vec<String> + Send + Sync + 'static) -> Self {
+        self.secondary_indexes
+            .write()
+            .unwrap()
+            .index_fns
+            .insert(name, Box::new(index_fn));
+        self
+    }
+
+    /// Returns a read handle to the store, which can be cloned freely
+    pub fn as_reader(&self) -> Store<K> {
+        Store {
+            store: self.store.clone(),
+            owner_index: self.owner_index.clone(),
+            secondary_indexes: self.secondary_indexes.clone(),
+        }
+    }
+
+    /// Applies a single `watcher::Event` to the store
+    ///
+    /// Do not call this from more than one `watcher`: a `Restarted` here always replaces the whole
+    /// store, so two interleaved `watcher`s would keep wiping out each other's objects. Use
+    /// [`apply_watcher_event_for_source`](Self::apply_watcher_event_for_source) instead if several
+    /// `watcher`s must feed into this `Writer`.
+    pub fn apply_watcher_event(&mut self, event: &watcher::Event<K>) {
+        match event {
+            watcher::Event::Applied(obj) => self.apply_single(obj),
+            watcher::Event::Deleted(obj) => self.remove_single(&ObjectRef::from_obj(obj)),
+            watcher::Event::Restarted(new_objs) => {
+                self.owner_index.write().unwrap().restart(new_objs);
+                self.secondary_indexes.write().unwrap().restart(new_objs);
+                *self.store.write().unwrap() = new_objs
+                    .iter()
+                    .map(|obj| (ObjectRef::from_obj(obj), Arc::new(obj.clone())))
+                    .collect();
+            }
+        }
+    }
+
+    /// Applies a single `watcher::Event` from the given `source_id` to the store
+    ///
+    /// Each `source_id` should be owned by exactly one `watcher`, watching a disjoint partition of
+    /// the keyspace (e.g. one namespace, or one kind). A `Restarted` only evicts and replaces the
+    /// objects this `Writer` previously saw from that same `source_id`, leaving objects reported by
+    /// other sources untouched — unlike `apply_watcher_event`, where any `Restarted` clears everything.
+    pub fn apply_watcher_event_for_source(&mut self, source_id: usize, event: &watcher::Event<K>) {
+        match event {
+            watcher::Event::Applied(obj) => {
+                let obj_ref = ObjectRef::from_obj(obj);
+                self.membership.entry(source_id).or_default().insert(obj_ref);
+                self.apply_single(obj);
+            }
+            watcher::Event::Deleted(obj) => {
+                let obj_ref = ObjectRef::from_obj(obj);
+                if let Some(seen) = self.membership.get_mut(&source_id) {
+                    seen.remove(&obj_ref);
+                }
+                self.remove_single(&obj_ref);
+            }
+            watcher::Event::Restarted(new_objs) => {
+                let new_refs = new_objs.iter().map(ObjectRef::from_obj).collect::<HashSet<_>>();
+                let stale_refs = self.membership.insert(source_id, new_refs.clone()).unwrap_or_default();
+                for stale_ref in stale_refs.difference(&new_refs) {
+                    self.remove_single(stale_ref);
+                }
+                for obj in new_objs {
+                    self.apply_single(obj);
+                }
+            }
+        }
+    }
+
+    fn apply_single(&mut self, obj: &K) {
+        self.owner_index.write().unwrap().insert(obj);
+        self.secondary_indexes.write().unwrap().insert(obj);
+        self.store
+            .write()
+            .unwrap()
+            .insert(ObjectRef::from_obj(obj), Arc::new(obj.clone()));
+    }
+
+    fn remove_single(&mut self, obj_ref: &ObjectRef<K>) {
+        self.owner_index.write().unwrap().remove(obj_ref);
+        self.secondary_indexes.write().unwrap().remove(obj_ref);
+        self.store.write().unwrap().remove(obj_ref);
+    }
+
+    /// Registers a new subscriber, returning a handle that starts receiving events from this point on
+    ///
+    /// `buffer` bounds how far a subscriber may fall behind before [`Writer::dispatch_event`] starts
+    /// blocking on it (see [`Dispatcher::broadcast`]).
+    #[cfg(feature = "unstable-runtime-subscribe")]
+    pub async fn subscribe(&mut self, buffer: usize) -> ReflectHandle<K> {
+        let (tx, rx) = mpsc::channel(buffer);
+        self.dispatcher.lock().await.subscribers.push(tx);
+        ReflectHandle {
+            reader: self.as_reader(),
+            dispatcher: self.dispatcher.clone(),
+            rx,
+        }
+    }
+
+    /// Broadcasts `event` to all current subscribers, flattened into its constituent objects
+    ///
+    /// Must be called after `apply_watcher_event`, so that a subscriber that looks the object up in
+    /// the `Store` as soon as it receives it is guaranteed to see at least that version.
+    #[cfg(feature = "unstable-runtime-subscribe")]
+    pub(crate) async fn dispatch_event(&mut self, event: &watcher::Event<K>) {
+        let mut dispatcher = self.dispatcher.lock().await;
+        if dispatcher.subscribers.is_empty() {
+            return;
+        }
+        for obj in event.clone().into_iter_touched() {
+            dispatcher.broadcast(Arc::new(obj)).await;
+        }
+    }
+}
+
+/// Creates a [`Writer`] and an initial [`ReflectHandle`] subscribed to it
+///
+/// Use this instead of `Writer::default()` when more than one consumer needs to see every event a
+/// single `watcher` stream produces (via [`reflector`](super::reflector)): one `watcher` feeds the
+/// `Writer`, and each consumer gets its own handle via `ReflectHandle::resubscribe`.
+#[cfg(feature = "unstable-runtime-subscribe")]
+pub async fn store_shared<K: RuntimeResource>(buffer: usize) -> (Writer<K>, ReflectHandle<K>) {
+    let mut writer = Writer::default();
+    let handle = writer.subscribe(buffer).await;
+    (writer, handle)
+}
@@ -0,0 +1,123 @@
+//! Watches a Kubernetes resource and reconnects to the apiserver as needed
+use futures::{stream, Stream, StreamExt};
+use kube::api::{Api, ListParams, Meta, WatchEvent};
+use serde::de::DeserializeOwned;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to perform initial object list: {0}")]
+    InitialListFailed(#[source] kube::Error),
+    #[error("failed to start watching object: {0}")]
+    WatchStartFailed(#[source] kube::Error),
+    #[error("error returned by apiserver during watch: {0}")]
+    WatchError(#[source] kube::error::ErrorResponse),
+    #[error("watch stream failed: {0}")]
+    WatchFailed(#[source] kube::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Progress of a watch, as watched by a single `watcher`
+///
+/// Can apply to a single Kubernetes object, or a list of Kubernetes objects
+#[derive(Clone, Debug)]
+pub enum Event<K> {
+    /// An object was added or modified
+    Applied(K),
+    /// An object was deleted
+    ///
+    /// NOTE: This should not be used for managing persistent state, since it's only emitted for
+    /// items that have not been `Restarted`.
+    Deleted(K),
+    /// The watch stream was restarted, and all contents should be replaced
+    Restarted(Vec<K>),
+}
+
+impl<K> Event<K> {
+    /// Flattens `self` into every object it touched, in the order they were touched
+    ///
+    /// `Applied` and `Deleted` each yield their one object; `Restarted` yields its whole batch.
+    pub fn into_iter_touched(self) -> impl Iterator<Item = K> {
+        match self {
+            Self::Applied(obj) | Self::Deleted(obj) => vec![obj],
+            Self::Restarted(objs) => objs,
+        }
+        .into_iter()
+    }
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    Empty,
+    InitListed { resource_version: String },
+    Watching { resource_version: String },
+}
+
+/// Watches a Kubernetes resource for changes, automatically handling the list/watch cycle
+///
+/// A watch stream that ends cleanly is reopened from the last seen `resourceVersion`. Any error is
+/// yielded and followed by a fresh list (after a one-second pause if the watch could not be
+/// started); a `410 Gone` yields an empty `Restarted` before that fresh list.
+pub fn watcher<K: Meta + Clone + DeserializeOwned + Send + 'static>(
+    api: Api<K>,
+    list_params: ListParams,
+) -> impl Stream<Item = Result<Event<K>>> {
+    stream::unfold(State::Empty, move |mut state| {
+        let api = api.clone();
+        let list_params = list_params.clone();
+        async move {
+            // Looped rather than a single match so a bookmark or a clean end-of-stream can advance
+            // `state` and reconnect without yielding a store-clearing `Event` downstream.
+            loop {
+                match state {
+                    State::Empty => match api.list(&list_params).await {
+                        Ok(list) => {
+                            let resource_version = list.metadata.resource_version.unwrap_or_default();
+                            return Some((
+                                Ok(Event::Restarted(list.items)),
+                                State::InitListed { resource_version },
+                            ));
+                        }
+                        Err(err) => return Some((Err(Error::InitialListFailed(err)), State::Empty)),
+                    },
+                    State::InitListed { resource_version } | State::Watching { resource_version } => {
+                        match api.watch(&list_params, &resource_version).await {
+                            Ok(stream) => match Box::pin(stream).next().await {
+                                Some(Ok(WatchEvent::Added(obj))) | Some(Ok(WatchEvent::Modified(obj))) => {
+                                    let resource_version = obj.resource_ver().unwrap_or(resource_version);
+                                    return Some((Ok(Event::Applied(obj)), State::Watching { resource_version }));
+                                }
+                                Some(Ok(WatchEvent::Deleted(obj))) => {
+                                    let resource_version = obj.resource_ver().unwrap_or(resource_version);
+                                    return Some((Ok(Event::Deleted(obj)), State::Watching { resource_version }));
+                                }
+                                Some(Ok(WatchEvent::Bookmark(bookmark))) => {
+                                    // Just a resource_version checkpoint: nothing in the store actually changed.
+                                    state = State::Watching {
+                                        resource_version: bookmark.metadata.resource_version,
+                                    };
+                                }
+                                Some(Ok(WatchEvent::Error(err))) if err.code == 410 => {
+                                    return Some((Ok(Event::Restarted(vec![])), State::Empty));
+                                }
+                                Some(Ok(WatchEvent::Error(err))) => {
+                                    return Some((Err(Error::WatchError(err)), State::Empty));
+                                }
+                                Some(Err(err)) => return Some((Err(Error::WatchFailed(err)), State::Empty)),
+                                None => {
+                                    // The watch ended cleanly; just reconnect from where we left off.
+                                    state = State::InitListed { resource_version };
+                                }
+                            },
+                            Err(err) => {
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                                return Some((Err(Error::WatchStartFailed(err)), State::Empty));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}